@@ -1,9 +1,13 @@
 use worker::*;
 mod utils;
-use utils::{find_icon_url, fetch_and_scale_icon};
+use utils::{build_provider_url, etag_for, fallback_icon, fetch_and_scale_icon, find_icon_url, IconSource, OutputFormat};
+
+/// Used when the `POSITIVE_CACHE_TTL`/`NEGATIVE_CACHE_TTL` env vars aren't set.
+const DEFAULT_POSITIVE_CACHE_TTL: u32 = 86400;
+const DEFAULT_NEGATIVE_CACHE_TTL: u32 = 60;
 
 #[event(fetch)]
-pub async fn main(req: Request, _env: Env, _ctx: Context) -> Result<Response> {
+pub async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
     let url = req.url()?;
     let path_segments = url.path_segments().map(|c| c.collect::<Vec<_>>()).unwrap_or_default();
 
@@ -11,31 +15,140 @@ pub async fn main(req: Request, _env: Env, _ctx: Context) -> Result<Response> {
         return Response::error("URL must be in the format /[url-to-website]/[size]", 400);
     }
 
-    let target_url = format!("https://{}", path_segments[0]);
+    let domain = path_segments[0];
+    if !utils::is_valid_domain(domain) || utils::is_blocked_hostname(domain) {
+        return Response::error("Invalid domain", 400);
+    }
+
+    let target_url = format!("https://{}", domain);
+    if !utils::is_safe_fetch_target(&target_url) {
+        return Response::error("Invalid domain", 400);
+    }
     let size = path_segments.get(1).and_then(|s| s.parse::<u32>().ok()).unwrap_or(64);
 
-    if size > 512 {
-        return Response::error("Maximum size is 512 pixels", 400);
+    if size == 0 || size > 512 {
+        return Response::error("Size must be between 1 and 512 pixels", 400);
     }
-    
-    match utils::is_website_up(&target_url).await {
-        Ok(true) => {},
-        Ok(false) => return Response::error("Website is not accessible", 404),
-        Err(_) => return Response::error("Failed to check website accessibility", 500),
+
+    let format = url
+        .query_pairs()
+        .find(|(k, _)| k == "format")
+        .and_then(|(_, v)| OutputFormat::parse(&v))
+        .unwrap_or(OutputFormat::Png);
+    let fallback_enabled = !url.query_pairs().any(|(k, v)| k == "fallback" && v == "false");
+
+    let provider = env.var("ICON_PROVIDER").map(|v| v.to_string()).unwrap_or_else(|_| "internal".to_string());
+    let provider_template = env.var("ICON_PROVIDER_TEMPLATE").ok().map(|v| v.to_string());
+
+    if provider != "internal" && url.query_pairs().any(|(k, v)| k == "redirect" && v == "true") {
+        let provider_url = match build_provider_url(&provider, provider_template.as_deref(), domain) {
+            Ok(provider_url) => provider_url,
+            Err(e) => return Response::error(e.to_string(), 400),
+        };
+        return Response::redirect(Url::parse(&provider_url)?);
     }
 
-    let icon_url = match find_icon_url(&target_url).await {
-        Ok(Some(url)) => url,
-        Ok(None) => return Response::error("No icon found", 404),
-        Err(_) => return Response::error("Error finding icon", 500),
-    };
+    let positive_ttl = cache_ttl(&env, "POSITIVE_CACHE_TTL", DEFAULT_POSITIVE_CACHE_TTL);
+    let negative_ttl = cache_ttl(&env, "NEGATIVE_CACHE_TTL", DEFAULT_NEGATIVE_CACHE_TTL);
+
+    let cache = Cache::default();
+    let cache_key = format!(
+        "https://favicon-rustler.internal/cache/{}/{}/{}/{}/{}",
+        provider,
+        domain.to_lowercase(),
+        size,
+        format.content_type(),
+        fallback_enabled,
+    );
+    let cache_request = Request::new(&cache_key, Method::Get)?;
+
+    if let Some(cached) = cache.get(&cache_request, false).await? {
+        return Ok(cached);
+    }
 
-    match fetch_and_scale_icon(&icon_url, size).await {
+    let mut response = match resolve_icon(&target_url, size, format, &provider, provider_template.as_deref(), domain).await {
         Ok(data) => {
             let mut headers = Headers::new();
-            headers.set("Content-Type", "image/png")?;
-            Response::from_bytes(data).map(|resp| resp.with_headers(headers))
+            headers.set("Content-Type", format.content_type())?;
+            headers.set("Cache-Control", &format!("public, max-age={}", positive_ttl))?;
+            headers.set("ETag", &etag_for(&data))?;
+            Response::from_bytes(data)?.with_headers(headers)
+        },
+        Err((status, message)) if fallback_enabled && is_fallback_eligible(status) => {
+            match fallback_icon(size, format) {
+                Ok(data) => {
+                    let mut headers = Headers::new();
+                    headers.set("Content-Type", format.content_type())?;
+                    headers.set("Cache-Control", &format!("public, max-age={}", negative_ttl))?;
+                    headers.set("X-Favicon-Fallback", "true")?;
+                    Response::from_bytes(data)?.with_headers(headers)
+                },
+                Err(_) => {
+                    let mut headers = Headers::new();
+                    headers.set("Cache-Control", &format!("public, max-age={}", negative_ttl))?;
+                    Response::error(message, status)?.with_headers(headers)
+                },
+            }
+        },
+        Err((status, message)) => {
+            let mut headers = Headers::new();
+            headers.set("Cache-Control", &format!("public, max-age={}", negative_ttl))?;
+            Response::error(message, status)?.with_headers(headers)
         },
-        Err(_) => Response::error("Failed to fetch or scale the icon", 500),
+    };
+
+    cache.put(&cache_request, response.cloned()?).await?;
+
+    Ok(response)
+}
+
+/// Statuses that mean "no icon to serve" (missing/unreachable/unscalable),
+/// as opposed to an operator misconfiguration (e.g. a bad `ICON_PROVIDER`
+/// setup), which should still surface as an error even with `fallback=true`.
+fn is_fallback_eligible(status: u16) -> bool {
+    matches!(status, 404 | 500 | 502)
+}
+
+/// Reads a cache TTL (in seconds) from an `Env` var, falling back to `default` when unset or unparseable.
+fn cache_ttl(env: &Env, var_name: &str, default: u32) -> u32 {
+    env.var(var_name)
+        .ok()
+        .and_then(|v| v.to_string().parse::<u32>().ok())
+        .unwrap_or(default)
+}
+
+/// Runs the icon resolution pipeline, collapsing every failure mode into a
+/// cacheable `(status, message)` pair. When `provider` isn't `"internal"`,
+/// the upstream provider's icon is fetched and rescaled instead of scraping
+/// `target_url` directly.
+async fn resolve_icon(
+    target_url: &str,
+    size: u32,
+    format: OutputFormat,
+    provider: &str,
+    provider_template: Option<&str>,
+    domain: &str,
+) -> std::result::Result<Vec<u8>, (u16, String)> {
+    if provider != "internal" {
+        let provider_url = build_provider_url(provider, provider_template, domain).map_err(|e| (400, e.to_string()))?;
+        return fetch_and_scale_icon(&IconSource::Url(provider_url), size, format)
+            .await
+            .map_err(|_| (502, "Failed to fetch icon from provider".to_string()));
+    }
+
+    match utils::is_website_up(target_url).await {
+        Ok(true) => {},
+        Ok(false) => return Err((404, "Website is not accessible".to_string())),
+        Err(_) => return Err((500, "Failed to check website accessibility".to_string())),
     }
+
+    let icon_source = match find_icon_url(target_url, size).await {
+        Ok(Some(source)) => source,
+        Ok(None) => return Err((404, "No icon found".to_string())),
+        Err(_) => return Err((500, "Error finding icon".to_string())),
+    };
+
+    fetch_and_scale_icon(&icon_source, size, format)
+        .await
+        .map_err(|_| (500, "Failed to fetch or scale the icon".to_string()))
 }
@@ -1,12 +1,107 @@
 use worker::*;
 use std::fmt;
+use std::net::IpAddr;
 use soup::{Soup, QueryBuilderExt, NodeExt};
 use url::Url as StdUrl;
 use image::{ImageError, ImageOutputFormat};
 use serde_json::Value as JsonValue;
+use base64::Engine;
+
+/// Returns true when `domain` looks like a safe hostname to build a URL from:
+/// non-empty, not absurdly long, free of path separators or `..`, and made up
+/// only of the characters a real hostname could contain.
+pub fn is_valid_domain(domain: &str) -> bool {
+    if domain.is_empty() || domain.len() > 255 {
+        return false;
+    }
+    if domain.contains("..") || domain.contains('/') {
+        return false;
+    }
+    domain
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'))
+}
+
+/// Returns true when `ip` falls in a private, loopback, link-local or
+/// unique-local range that this worker must never be tricked into fetching.
+fn is_disallowed_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+        }
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || (ip.segments()[0] & 0xfe00) == 0xfc00 // fc00::/7 unique local
+                || (ip.segments()[0] & 0xffc0) == 0xfe80 // fe80::/10 link local
+        }
+    }
+}
+
+/// Hostnames that are either always loopback/cloud-metadata aliases, or
+/// wildcard-DNS rebinding services whose label resolves to whatever address
+/// the caller encodes in it (e.g. `169-254-169-254.nip.io`). Since Workers
+/// doesn't expose a DNS lookup API to vet an arbitrary hostname's resolved
+/// address before `fetch()` performs it, these are blocked by name instead.
+pub fn is_blocked_hostname(domain: &str) -> bool {
+    let domain = domain.to_ascii_lowercase();
+    const BLOCKED_EXACT: &[&str] = &["localhost", "metadata", "metadata.google.internal"];
+    const WILDCARD_DNS_ROOTS: &[&str] = &["nip.io", "sslip.io", "xip.io"];
+    const BLOCKED_SUFFIXES: &[&str] = &[".localhost", ".internal"];
+
+    BLOCKED_EXACT.contains(&domain.as_str())
+        || WILDCARD_DNS_ROOTS.iter().any(|root| domain == *root || domain.ends_with(&format!(".{}", root)))
+        || BLOCKED_SUFFIXES.iter().any(|suffix| domain.ends_with(suffix))
+}
+
+/// Returns true when `url` is safe to fetch: an http(s) URL whose host is a
+/// well-formed, non-blocked domain name or a non-private IP literal. This
+/// guards every outbound fetch so the worker can't be used to probe internal
+/// networks. Public so callers can reject an unsafe target up front with a
+/// 400 instead of letting it fail deeper in the pipeline as a generic error.
+pub fn is_safe_fetch_target(url: &str) -> bool {
+    let parsed = match StdUrl::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return false,
+    };
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return false;
+    }
+    match parsed.host() {
+        Some(url::Host::Domain(domain)) => is_valid_domain(domain) && !is_blocked_hostname(domain),
+        Some(url::Host::Ipv4(ip)) => !is_disallowed_ip(&IpAddr::V4(ip)),
+        Some(url::Host::Ipv6(ip)) => !is_disallowed_ip(&IpAddr::V6(ip)),
+        None => false,
+    }
+}
+
+/// Presented on every outbound fetch so sites that gate unknown clients still
+/// respond normally, mirroring favicon-rover's `BOT_USER_AGENT` approach.
+const BOT_USER_AGENT: &str =
+    "Mozilla/5.0 (compatible; FaviconRustlerBot/1.0; +https://github.com/Lissy93/favicon-rustler)";
+
+/// Builds a `Request` carrying a browser-like `User-Agent`, `Accept` and
+/// `Accept-Language`. Every `Fetch` in this crate should be routed through
+/// this rather than bare `Fetch::Url(...)`.
+fn build_request(url: &str, method: Method) -> Result<Request> {
+    let mut headers = Headers::new();
+    headers.set("User-Agent", BOT_USER_AGENT)?;
+    headers.set(
+        "Accept",
+        "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/*,*/*;q=0.8",
+    )?;
+    headers.set("Accept-Language", "en-US,en;q=0.9")?;
+
+    let mut init = RequestInit::new();
+    init.with_method(method).with_headers(headers);
+    Request::new_with_init(url, &init)
+}
 
 pub async fn is_website_up(url: &str) -> Result<bool> {
-    let response = Fetch::Url(Url::parse(url)?).send().await?;
+    if !is_safe_fetch_target(url) {
+        return Err(worker::Error::from("Refusing to fetch an unsafe target".to_string()));
+    }
+    let response = Fetch::Request(build_request(url, Method::Get)?).send().await?;
     Ok(response.status_code() >= 200 && response.status_code() < 300)
 }
 
@@ -24,39 +119,95 @@ impl fmt::Display for MyImageError {
     }
 }
 
-/// Finds the icon URL from the given website's HTML content or manifest
-pub async fn find_icon_url(url: &str) -> Result<Option<String>> {
-  let mut response = Fetch::Url(Url::parse(url)?).send().await?;
+/// Base score applied to a candidate before its declared size is considered,
+/// reflecting how reliable that kind of hint tends to be in practice.
+const PRIORITY_APPLE_TOUCH: u32 = 50;
+const PRIORITY_MANIFEST: u32 = 40;
+const PRIORITY_ICON: u32 = 30;
+const PRIORITY_OG_IMAGE: u32 = 20;
+const PRIORITY_WELL_KNOWN: u32 = 10;
+/// Inline `data:` icons skip a network fetch entirely, but a real URL is
+/// still preferred when one is available, so inline candidates rank last.
+const PRIORITY_INLINE: u32 = 5;
+// The external fallback service is only ever consulted once every other
+// candidate has been exhausted, so it needs no score of its own.
+
+/// Sentinel declared size for `sizes="any"` (typical of SVG icons), which can
+/// be rasterized at whatever size is requested.
+const SIZE_ANY: u32 = u32::MAX;
+
+/// Inline `data:` icon payloads are capped to keep a hostile page from
+/// smuggling an oversized blob through the HTML it serves us.
+const MAX_INLINE_ICON_BYTES: usize = 256 * 1024;
+
+/// Where a selected icon's bytes ultimately come from: a URL still to be
+/// fetched, or bytes already decoded from an inline `data:` URI.
+#[derive(Debug, PartialEq)]
+pub enum IconSource {
+    Url(String),
+    Inline(Vec<u8>),
+}
+
+/// A candidate icon discovered while scanning a page, scored so the best one
+/// can be picked once every source has been collected.
+struct IconCandidate {
+    source: IconSource,
+    priority: u32,
+    size: u32,
+}
+
+/// Finds the best icon URL for the given website's HTML content or manifest,
+/// scored against the requested pixel size
+pub async fn find_icon_url(url: &str, requested_size: u32) -> Result<Option<IconSource>> {
+  if !is_safe_fetch_target(url) {
+      return Err(worker::Error::from("Refusing to fetch an unsafe target".to_string()));
+  }
+  let mut response = Fetch::Request(build_request(url, Method::Get)?).send().await?;
   let html = response.text().await?;
   let soup = Soup::new(&html);
   let base_url = StdUrl::parse(url)?;
 
+  let mut candidates = Vec::new();
+
   let icons = [
-      ("apple-touch-icon", "href"),
-      ("icon", "href"),
-      ("shortcut icon", "href"),
-      ("link[rel='manifest']", "href"),
+      ("apple-touch-icon", "href", PRIORITY_APPLE_TOUCH),
+      ("icon", "href", PRIORITY_ICON),
+      ("shortcut icon", "href", PRIORITY_ICON),
+      ("link[rel='manifest']", "href", PRIORITY_MANIFEST),
   ];
 
   console_log!("Fetching icon from URL: {}", url);
 
-  for &(icon, attr) in &icons {
+  for &(icon, attr, priority) in &icons {
       console_log!("Searching for icons of type: {}", icon);
       let elements = soup.tag(icon.split_once('[').unwrap_or((icon, "")).0).find_all();
 
       for element in elements {
           if let Some(link) = element.get(attr) {
               if icon == "link[rel='manifest']" {
-                  if let Ok(Some(icon_url)) = process_manifest(&link, &base_url).await {
-                      return Ok(Some(icon_url));
+                  if let Ok(manifest_candidates) = process_manifest(&link, &base_url).await {
+                      candidates.extend(manifest_candidates);
+                  }
+              } else if link.starts_with("data:") {
+                  if let Some(bytes) = parse_data_uri(&link) {
+                      let size = element.get("sizes").map(|s| parse_sizes_attr(&s)).unwrap_or(0);
+                      console_log!("Inline icon found (size={})", size);
+                      candidates.push(IconCandidate { source: IconSource::Inline(bytes), priority: PRIORITY_INLINE, size });
+                  } else {
+                      console_log!("Rejecting unsupported or oversized inline icon");
                   }
               } else {
                   let full_url = match link.starts_with("http://") || link.starts_with("https://") {
                       true => link.to_string(),  // Already absolute URL
                       false => base_url.join(&link)?.to_string(),  // Resolve relative URL
                   };
-                  console_log!("Icon found: {}", full_url);
-                  return Ok(Some(full_url));
+                  if !is_safe_fetch_target(&full_url) {
+                      console_log!("Rejecting unsafe icon target: {}", full_url);
+                      continue;
+                  }
+                  let size = element.get("sizes").map(|s| parse_sizes_attr(&s)).unwrap_or(0);
+                  console_log!("Icon found: {} (size={})", full_url, size);
+                  candidates.push(IconCandidate { source: IconSource::Url(full_url), priority, size });
               }
           }
       }
@@ -69,7 +220,7 @@ pub async fn find_icon_url(url: &str) -> Result<Option<String>> {
 
       if let Ok(true) = check_url_exists(&icon_url.to_string()).await {
         console_log!("Icon found in well-known location: {}", icon_url);
-        return Ok(Some(icon_url.to_string()));
+        candidates.push(IconCandidate { source: IconSource::Url(icon_url.to_string()), priority: PRIORITY_WELL_KNOWN, size: 0 });
       } else {
         console_log!("Failed to find icon in well-known location: {}", icon_url);
       }
@@ -79,16 +230,24 @@ pub async fn find_icon_url(url: &str) -> Result<Option<String>> {
   if let Some(og_image) = soup.tag("meta").attr("property", "og:image").find() {
     if let Some(content) = og_image.get("content") {
         let full_url = validate_and_construct_url(&content, &base_url)?;
-        console_log!("OG Image found: {}", full_url);
-        return Ok(Some(full_url));
+        if is_safe_fetch_target(&full_url) {
+            console_log!("OG Image found: {}", full_url);
+            candidates.push(IconCandidate { source: IconSource::Url(full_url), priority: PRIORITY_OG_IMAGE, size: 0 });
+        } else {
+            console_log!("Rejecting unsafe og:image target: {}", full_url);
+        }
     }
   }
 
+  if let Some(best) = pick_best_icon(candidates, requested_size) {
+      return Ok(Some(best));
+  }
+
   // Fallback to external service
   let fallback_url = format!("https://t3.gstatic.com/faviconV2?client=SOCIAL&type=FAVICON&fallback_opts=TYPE,SIZE,URL&url={}&size=128", url);
   if let Ok(true) = check_url_exists(&fallback_url).await {
     console_log!("Icon found using fallback service: {}", fallback_url);
-    return Ok(Some(fallback_url));
+    return Ok(Some(IconSource::Url(fallback_url)));
   } else {
     console_log!("Failed to verify icon at fallback service: {}", fallback_url);
   }
@@ -96,6 +255,62 @@ pub async fn find_icon_url(url: &str) -> Result<Option<String>> {
   Ok(None)
 }
 
+/// Parses a `sizes` attribute such as `"16x16"`, `"32x32 48x48"` or `"any"`,
+/// returning the largest declared dimension (`SIZE_ANY` for `any`, `0` if
+/// nothing usable is present).
+fn parse_sizes_attr(sizes: &str) -> u32 {
+    let mut best = 0;
+    for token in sizes.split_whitespace() {
+        if token.eq_ignore_ascii_case("any") {
+            return SIZE_ANY;
+        }
+        if let Some((w, h)) = token.split_once(['x', 'X']) {
+            if let (Ok(w), Ok(h)) = (w.parse::<u32>(), h.parse::<u32>()) {
+                best = best.max(w.min(h));
+            }
+        }
+    }
+    best
+}
+
+/// Picks the smallest candidate that is still at least as large as the
+/// requested size, falling back to the largest available candidate when none
+/// are big enough. Ties are broken by the rel-type priority. A `SIZE_ANY`
+/// (vector) candidate renders pixel-perfect at any size, so it counts as a
+/// zero-overhead exact match rather than being scored against `u32::MAX`.
+fn pick_best_icon(candidates: Vec<IconCandidate>, requested_size: u32) -> Option<IconSource> {
+    candidates.into_iter().max_by_key(|c| {
+        let fits = c.size >= requested_size;
+        let closeness = if fits {
+            let overhead = if c.size == SIZE_ANY { 0 } else { c.size.saturating_sub(requested_size) };
+            (u32::MAX - overhead, c.priority)
+        } else {
+            (c.size, c.priority)
+        };
+        (fits, closeness)
+    }).map(|c| c.source)
+}
+
+/// Parses a `data:` URI, returning the decoded bytes when it declares a
+/// base64-encoded `image/*` payload that fits within the inline size cap.
+fn parse_data_uri(href: &str) -> Option<Vec<u8>> {
+    let rest = href.strip_prefix("data:")?;
+    let (meta, data) = rest.split_once(',')?;
+    let mut parts = meta.split(';');
+    let mime = parts.next().unwrap_or("");
+    if !mime.starts_with("image/") {
+        return None;
+    }
+    if !parts.any(|p| p == "base64") {
+        return None;
+    }
+    let bytes = base64::engine::general_purpose::STANDARD.decode(data).ok()?;
+    if bytes.is_empty() || bytes.len() > MAX_INLINE_ICON_BYTES {
+        return None;
+    }
+    Some(bytes)
+}
+
 /// Validate and construct the full URL from a potential relative link
 fn validate_and_construct_url(link: &str, base_url: &StdUrl) -> Result<String> {
   if link.starts_with("http://") || link.starts_with("https://") {
@@ -105,13 +320,17 @@ fn validate_and_construct_url(link: &str, base_url: &StdUrl) -> Result<String> {
   }
 }
 
-/// Processes a manifest file to find icons
-async fn process_manifest(manifest_url: &str, base_url: &StdUrl) -> Result<Option<String>> {
+/// Processes a manifest file, returning a scored candidate for every icon it declares
+async fn process_manifest(manifest_url: &str, base_url: &StdUrl) -> Result<Vec<IconCandidate>> {
   // Parse the URL from the string, handling errors appropriately
   let parsed_url = StdUrl::parse(manifest_url).map_err(|e| worker::Error::from(e.to_string()))?;
 
+  if !is_safe_fetch_target(parsed_url.as_str()) {
+      return Err(worker::Error::from("Refusing to fetch an unsafe manifest target".to_string()));
+  }
+
   // Perform the fetch operation
-  let mut response = Fetch::Url(Url::parse(&parsed_url.to_string())?)
+  let mut response = Fetch::Request(build_request(parsed_url.as_str(), Method::Get)?)
       .send()
       .await
       .map_err(|e| worker::Error::from(e.to_string()))?;
@@ -120,6 +339,8 @@ async fn process_manifest(manifest_url: &str, base_url: &StdUrl) -> Result<Optio
   let manifest: JsonValue = response.json::<JsonValue>().await
       .map_err(|e| worker::Error::from(e.to_string()))?;
 
+  let mut candidates = Vec::new();
+
   // Look for the 'icons' array in the JSON structure
   if let Some(icons) = manifest["icons"].as_array() {
       for icon in icons {
@@ -129,40 +350,294 @@ async fn process_manifest(manifest_url: &str, base_url: &StdUrl) -> Result<Optio
                   true => icon_src.to_string(),
                   false => base_url.join(icon_src).map_err(|e| worker::Error::from(e.to_string()))?.to_string(),
               };
-              return Ok(Some(full_url));
+              if !is_safe_fetch_target(&full_url) {
+                  continue;
+              }
+              let size = icon["sizes"].as_str().map(parse_sizes_attr).unwrap_or(0);
+              candidates.push(IconCandidate { source: IconSource::Url(full_url), priority: PRIORITY_MANIFEST, size });
           }
       }
   }
-  Ok(None)
+  Ok(candidates)
 }
 
 
 /// Checks if a URL exists by performing a HEAD request
 async fn check_url_exists(url: &str) -> Result<bool> {
+  if !is_safe_fetch_target(url) {
+      return Ok(false);
+  }
   let parsed_url = StdUrl::parse(url).map_err(|e| worker::Error::from(e.to_string()))?;
-  let request = Request::new(&parsed_url.to_string(), Method::Head)?;
+  let request = build_request(parsed_url.as_str(), Method::Head)?;
   let response = Fetch::Request(request).send().await.map_err(|e| worker::Error::from(e.to_string()))?;
   Ok(response.status_code() == 200)
 }
 
 
-/// Fetches an image from the given URL, resizes it, and returns the raw bytes of the resized image.
-pub async fn fetch_and_scale_icon(url: &str, size: u32) -> Result<Vec<u8>> {
-    let mut response = Fetch::Url(Url::parse(url)?).send().await?;
-    if response.status_code() >= 200 && response.status_code() < 300 {
-        let bytes = response.bytes().await?;
-        resize_image(&bytes, size)  // Directly use the function without external map_err
-    } else {
-        Err(worker::Error::from(format!("Failed to fetch the original image: HTTP {}", response.status_code())))
+/// Fetches (or decodes, for an inline source) an icon, resizes it, and
+/// returns the raw bytes of the resized image.
+pub async fn fetch_and_scale_icon(source: &IconSource, size: u32, format: OutputFormat) -> Result<Vec<u8>> {
+    match source {
+        IconSource::Url(url) => {
+            if !is_safe_fetch_target(url) {
+                return Err(worker::Error::from("Refusing to fetch an unsafe target".to_string()));
+            }
+            let mut response = Fetch::Request(build_request(url, Method::Get)?).send().await?;
+            if response.status_code() >= 200 && response.status_code() < 300 {
+                let bytes = response.bytes().await?;
+                let hint_svg = url.to_lowercase().ends_with(".svg");
+                resize_image(&bytes, size, hint_svg, format)
+            } else {
+                Err(worker::Error::from(format!("Failed to fetch the original image: HTTP {}", response.status_code())))
+            }
+        }
+        IconSource::Inline(bytes) => resize_image(bytes, size, false, format),
+    }
+}
+
+
+/// Builds the upstream URL for a third-party icon provider. `custom` expects
+/// a `{}`-substituted `template`, e.g. `https://icons.example.com/{}.png`.
+pub fn build_provider_url(provider: &str, template: Option<&str>, domain: &str) -> Result<String> {
+    match provider {
+        "duckduckgo" => Ok(format!("https://icons.duckduckgo.com/ip3/{}.ico", domain)),
+        "google" => Ok(format!("https://www.google.com/s2/favicons?domain={}&sz=128", domain)),
+        "custom" => {
+            let template = template.ok_or_else(|| {
+                worker::Error::from("ICON_PROVIDER_TEMPLATE is required for the custom provider".to_string())
+            })?;
+            Ok(template.replace("{}", domain))
+        }
+        other => Err(worker::Error::from(format!("Unknown icon provider: {}", other))),
+    }
+}
+
+/// Computes a short, stable ETag for a byte buffer. FNV-1a is more than
+/// sufficient here since this is only used for cache validation, not security.
+pub fn etag_for(bytes: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("\"{:016x}\"", hash)
+}
+
+/// Output formats `resize_image` can encode to, selected via the `format`
+/// query param.
+#[derive(Clone, Copy)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Ico,
+}
+
+impl OutputFormat {
+    /// Parses a `format` query param value, returning `None` for anything unrecognized.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "png" => Some(OutputFormat::Png),
+            "jpeg" | "jpg" => Some(OutputFormat::Jpeg),
+            "webp" => Some(OutputFormat::WebP),
+            "ico" => Some(OutputFormat::Ico),
+            _ => None,
+        }
+    }
+
+    /// The `Content-Type` header value for this format.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Ico => "image/x-icon",
+        }
+    }
+
+    fn as_image_output_format(&self) -> ImageOutputFormat {
+        match self {
+            OutputFormat::Png => ImageOutputFormat::Png,
+            OutputFormat::Jpeg => ImageOutputFormat::Jpeg(90),
+            OutputFormat::WebP => ImageOutputFormat::WebP,
+            OutputFormat::Ico => ImageOutputFormat::Ico,
+        }
     }
 }
 
+/// Bundled favicon served when icon resolution fails and the caller hasn't
+/// opted out with `?fallback=false`.
+const FALLBACK_ICON: &[u8] = include_bytes!("assets/fallback.png");
 
-/// Resizes the image to the specified dimensions using the `image` crate.
-fn resize_image(image_data: &[u8], size: u32) -> Result<Vec<u8>> {
-    let img = image::load_from_memory(image_data).map_err(MyImageError)?;
-    let scaled = img.resize_exact(size, size, image::imageops::FilterType::Nearest);
+/// Resizes the bundled fallback icon to `size` and encodes it as `format`.
+pub fn fallback_icon(size: u32, format: OutputFormat) -> Result<Vec<u8>> {
+    resize_image(FALLBACK_ICON, size, false, format)
+}
+
+/// Returns true when `bytes` look like SVG markup, sniffed via a leading
+/// `<svg` or `<?xml` tag (ignoring a BOM or leading whitespace).
+fn looks_like_svg(bytes: &[u8]) -> bool {
+    let probe_len = bytes.len().min(256);
+    let probe = String::from_utf8_lossy(&bytes[..probe_len]);
+    let trimmed = probe.trim_start_matches('\u{feff}').trim_start();
+    trimmed.starts_with("<svg") || trimmed.starts_with("<?xml")
+}
+
+/// Resizes the image to the specified dimensions and encodes it as `format`,
+/// rasterizing SVG input (content-sniffed, or hinted by a `.svg` source URL)
+/// before falling back to the `image` crate's raster decoder for everything else.
+fn resize_image(image_data: &[u8], size: u32, hint_svg: bool, format: OutputFormat) -> Result<Vec<u8>> {
+    let img = if hint_svg || looks_like_svg(image_data) {
+        image::DynamicImage::ImageRgba8(rasterize_svg(image_data, size)?)
+    } else {
+        image::load_from_memory(image_data)
+            .map_err(MyImageError)?
+            .resize_exact(size, size, image::imageops::FilterType::Nearest)
+    };
     let mut result = Vec::new();
-    scaled.write_to(&mut result, ImageOutputFormat::Png).map_err(MyImageError)?;
+    img.write_to(&mut result, format.as_image_output_format()).map_err(MyImageError)?;
     Ok(result)
 }
+
+/// Rasterizes SVG bytes into an RGBA buffer at the requested size using a
+/// pure-Rust pipeline: `usvg` parses the document, `resvg` renders it onto a
+/// `tiny-skia` canvas sized to match the request.
+fn rasterize_svg(svg_data: &[u8], size: u32) -> Result<image::RgbaImage> {
+    let opts = usvg::Options::default();
+    let tree = usvg::Tree::from_data(svg_data, &opts)
+        .map_err(|e| worker::Error::from(format!("Failed to parse SVG: {}", e)))?;
+
+    let mut pixmap = tiny_skia::Pixmap::new(size, size)
+        .ok_or_else(|| worker::Error::from("Invalid icon size".to_string()))?;
+
+    let tree_size = tree.size();
+    let scale_x = size as f32 / tree_size.width();
+    let scale_y = size as f32 / tree_size.height();
+    let transform = tiny_skia::Transform::from_scale(scale_x, scale_y);
+
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    image::RgbaImage::from_raw(size, size, pixmap.data().to_vec())
+        .ok_or_else(|| worker::Error::from("Failed to build image buffer from rasterized SVG".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sizes_attr_picks_the_largest_declared_dimension() {
+        assert_eq!(parse_sizes_attr("16x16"), 16);
+        assert_eq!(parse_sizes_attr("16x16 32x32 48x48"), 48);
+        assert_eq!(parse_sizes_attr("not-a-size"), 0);
+    }
+
+    #[test]
+    fn parse_sizes_attr_treats_any_as_infinite() {
+        assert_eq!(parse_sizes_attr("any"), SIZE_ANY);
+        assert_eq!(parse_sizes_attr("ANY"), SIZE_ANY);
+    }
+
+    #[test]
+    fn pick_best_icon_prefers_the_smallest_fit_over_a_tiny_icon() {
+        let candidates = vec![
+            IconCandidate { source: IconSource::Url("tiny.png".to_string()), priority: PRIORITY_ICON, size: 16 },
+            IconCandidate { source: IconSource::Url("just-right.png".to_string()), priority: PRIORITY_ICON, size: 64 },
+            IconCandidate { source: IconSource::Url("huge.png".to_string()), priority: PRIORITY_ICON, size: 512 },
+        ];
+        assert_eq!(pick_best_icon(candidates, 64), Some(IconSource::Url("just-right.png".to_string())));
+    }
+
+    #[test]
+    fn pick_best_icon_falls_back_to_the_largest_when_nothing_fits() {
+        let candidates = vec![
+            IconCandidate { source: IconSource::Url("small.png".to_string()), priority: PRIORITY_ICON, size: 16 },
+            IconCandidate { source: IconSource::Url("smaller.png".to_string()), priority: PRIORITY_ICON, size: 8 },
+        ];
+        assert_eq!(pick_best_icon(candidates, 64), Some(IconSource::Url("small.png".to_string())));
+    }
+
+    #[test]
+    fn pick_best_icon_ranks_any_sized_svg_at_least_as_well_as_an_exact_raster_match() {
+        let candidates = vec![
+            IconCandidate { source: IconSource::Url("huge.png".to_string()), priority: PRIORITY_APPLE_TOUCH, size: 512 },
+            IconCandidate { source: IconSource::Url("vector.svg".to_string()), priority: PRIORITY_APPLE_TOUCH, size: SIZE_ANY },
+        ];
+        assert_eq!(pick_best_icon(candidates, 64), Some(IconSource::Url("vector.svg".to_string())));
+    }
+
+    #[test]
+    fn pick_best_icon_breaks_ties_by_priority() {
+        let candidates = vec![
+            IconCandidate { source: IconSource::Url("manifest.png".to_string()), priority: PRIORITY_MANIFEST, size: 64 },
+            IconCandidate { source: IconSource::Url("apple-touch.png".to_string()), priority: PRIORITY_APPLE_TOUCH, size: 64 },
+        ];
+        assert_eq!(pick_best_icon(candidates, 64), Some(IconSource::Url("apple-touch.png".to_string())));
+    }
+
+    #[test]
+    fn is_valid_domain_accepts_well_formed_hostnames() {
+        assert!(is_valid_domain("example.com"));
+        assert!(is_valid_domain("sub.example-site.com"));
+    }
+
+    #[test]
+    fn is_valid_domain_rejects_path_separators_traversal_and_oversized_input() {
+        assert!(!is_valid_domain(""));
+        assert!(!is_valid_domain("example.com/evil"));
+        assert!(!is_valid_domain("example..com"));
+        assert!(!is_valid_domain(&"a".repeat(256)));
+    }
+
+    #[test]
+    fn is_blocked_hostname_rejects_loopback_metadata_and_dns_rebinding_aliases() {
+        assert!(is_blocked_hostname("localhost"));
+        assert!(is_blocked_hostname("foo.localhost"));
+        assert!(is_blocked_hostname("metadata.google.internal"));
+        assert!(is_blocked_hostname("service.internal"));
+        assert!(is_blocked_hostname("169-254-169-254.nip.io"));
+        assert!(!is_blocked_hostname("example.com"));
+    }
+
+    #[test]
+    fn is_disallowed_ip_rejects_private_loopback_and_link_local_ranges() {
+        assert!(is_disallowed_ip(&"127.0.0.1".parse().unwrap()));
+        assert!(is_disallowed_ip(&"10.0.0.1".parse().unwrap()));
+        assert!(is_disallowed_ip(&"172.16.0.1".parse().unwrap()));
+        assert!(is_disallowed_ip(&"192.168.1.1".parse().unwrap()));
+        assert!(is_disallowed_ip(&"169.254.169.254".parse().unwrap()));
+        assert!(is_disallowed_ip(&"::1".parse().unwrap()));
+        assert!(!is_disallowed_ip(&"8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn is_safe_fetch_target_rejects_ip_literal_ssrf_attempts() {
+        assert!(!is_safe_fetch_target("https://127.0.0.1/"));
+        assert!(!is_safe_fetch_target("https://169.254.169.254/"));
+        assert!(!is_safe_fetch_target("ftp://example.com/"));
+        assert!(is_safe_fetch_target("https://example.com/"));
+    }
+
+    #[test]
+    fn parse_data_uri_decodes_a_valid_base64_image() {
+        let bytes = parse_data_uri("data:image/png;base64,aGVsbG8=").expect("should decode");
+        assert_eq!(bytes, b"hello");
+    }
+
+    #[test]
+    fn parse_data_uri_rejects_non_image_mime_types() {
+        assert!(parse_data_uri("data:text/plain;base64,aGVsbG8=").is_none());
+    }
+
+    #[test]
+    fn parse_data_uri_rejects_non_base64_payloads() {
+        assert!(parse_data_uri("data:image/svg+xml,<svg/>").is_none());
+    }
+
+    #[test]
+    fn parse_data_uri_rejects_payloads_over_the_size_cap() {
+        let oversized = "A".repeat(MAX_INLINE_ICON_BYTES * 2);
+        let href = format!("data:image/png;base64,{}", oversized);
+        assert!(parse_data_uri(&href).is_none());
+    }
+}